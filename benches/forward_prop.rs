@@ -0,0 +1,55 @@
+// Wire this up as a `[[bench]]` target in Cargo.toml to run with `cargo bench`.
+// Reports GFLOP/s for `NeuralNetwork::predict` across a mini-batch, to confirm the
+// batched GEMM rewrite (replacing the old column-by-column forward/backprop) is
+// actually faster than doing one example at a time.
+
+use std::time::Instant;
+
+use nalgebra::DMatrix;
+use rand::SeedableRng;
+use rand_pcg::Pcg32;
+
+use rust_nn::neunet::api::defs::{ActivationType, HeUniform, LayerDefinition, NeuralNetworkArchitecture, Prediction};
+use rust_nn::neunet::definitions::NeuralNetwork;
+
+fn main() {
+    let num_features = 784;
+    let batch_size = 256;
+    let iterations = 50;
+
+    let arch = NeuralNetworkArchitecture {
+        num_features,
+        num_classes: 10,
+        layers: vec![
+            LayerDefinition { activation_type: ActivationType::Relu, num_activations: 128 },
+            LayerDefinition { activation_type: ActivationType::SoftMax, num_activations: 10 },
+        ],
+        rand_initializer: HeUniform,
+    };
+
+    let mut rng = Pcg32::seed_from_u64(7);
+    let mut network = NeuralNetwork::new(&arch, &mut rng);
+
+    let data = DMatrix::from_fn(num_features, batch_size, |_, _| rand::random::<f32>());
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        network.predict(&data);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    // 2 FLOPs per multiply-add, one GEMM per layer: (inputs x activations x batch).
+    let flops_per_pass: f64 = {
+        let mut total = 0usize;
+        let mut inputs = num_features;
+        for layer in &arch.layers {
+            total += 2 * inputs * layer.num_activations * batch_size;
+            inputs = layer.num_activations;
+        }
+        total as f64
+    };
+
+    let gflops = (flops_per_pass * iterations as f64) / elapsed / 1e9;
+
+    println!("forward_prop: {:.2} GFLOP/s over {} iterations ({:.4}s total)", gflops, iterations, elapsed);
+}
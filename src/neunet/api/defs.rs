@@ -1,15 +1,26 @@
 #![allow(dead_code)]
 
+use std::fs;
+
 use nalgebra::{DMatrix, DVector};
 use nalgebra::*;
 use rand_distr::{Distribution, Normal};
-use serde_json::json;
+use serde_json::{json, Value};
+
+use crate::neunet::criterion::{criterion_from_name, Criterion, Reduction};
 
 pub enum ActivationType {
     Sigmoid,
     Relu,
     Tanh,
     SoftMax,
+    // Softmax with an implicit, always-zero logit added to the denominator, so every
+    // output probability can shrink toward zero when no class is strongly activated.
+    QuietSoftMax,
+    // a = z, unchanged. The only output activation `MeanSquaredError` can be validly
+    // paired with, since its `output_delta` assumes `dz = g'(z) * (y_hat - y) * 2` with
+    // `g'(z) = 1`.
+    Identity,
 }
 
 
@@ -28,6 +39,7 @@ pub struct NNModel {
 }
 
 
+#[derive(Debug, Clone)]
 pub enum OptimizationType {
     MBGD,
     Momentum,
@@ -35,6 +47,18 @@ pub enum OptimizationType {
     Adam,
 }
 
+impl OptimizationType {
+    fn from_str(s: &str) -> Result<OptimizationType, Box<dyn std::error::Error>> {
+        match s {
+            "MBGD" => Ok(OptimizationType::MBGD),
+            "Momentum" => Ok(OptimizationType::Momentum),
+            "RMSProp" => Ok(OptimizationType::RMSProp),
+            "Adam" => Ok(OptimizationType::Adam),
+            other => Err(format!("unknown optimization type '{}'", other).into()),
+        }
+    }
+}
+
 pub struct HyperParams {
     pub max_accuracy_threshold: f32,
     pub max_epochs: u32,
@@ -44,6 +68,7 @@ pub struct HyperParams {
     pub learning_rate: f32,
     pub optimization_type: OptimizationType,
     pub l2_regularization: Option<f32>,
+    pub criterion: Box<dyn Criterion>,
 }
 
 impl Default for HyperParams {
@@ -57,6 +82,7 @@ impl Default for HyperParams {
             learning_rate: 0.01,
             optimization_type: OptimizationType::Adam,
             l2_regularization: None,
+            criterion: Box::new(crate::neunet::criterion::BinaryCrossEntropy { reduction: Reduction::Mean }),
         }
     }
 }
@@ -81,7 +107,11 @@ pub struct TrainingEval {
 
 #[derive(Debug)]
 pub struct Metrics {
+    // Criterion loss plus the l2_regularization penalty, when one is configured; this is
+    // the value `StochasticGradientDescent::optimize` compares against `stop_cost_quota`.
     pub loss: f32,
+    // Criterion loss alone, with no l2_regularization penalty folded in.
+    pub raw_loss: f32,
     pub train_eval: TrainingEval,
     pub test_eval: TrainingEval,
 }
@@ -109,6 +139,7 @@ impl Json for TrainingMessage {
         match &self.metrics {
             Some(metrics) => {
                 map.insert("loss".to_string(), json!(metrics.loss));
+                map.insert("raw_loss".to_string(), json!(metrics.raw_loss));
                 map.insert("train_eval".to_string(),
                            json!({
                                   "confusion_matrix" : json!({
@@ -170,6 +201,7 @@ impl TrainingObserver for ConsoleObserver {
         match msg.metrics {
             Some(m) => {
                 println!("\t loss {}", m.loss);
+                println!("\t raw loss {}", m.raw_loss);
                 println!("\t train accuracy {}", m.train_eval.accuracy);
                 println!("\t test accuracy {}", m.test_eval.accuracy);
             }
@@ -198,11 +230,11 @@ pub struct Layer {
     pub weights: DMatrix<f32>,
     pub activation_type: ActivationType,
 
-    // W * X + B
-    pub z: DVector<f32>,
-    // activation(z)
-    pub a: DVector<f32>,
-    pub dz: DVector<f32>,
+    // W * A_prev + b, one column per example in the mini-batch
+    pub z: DMatrix<f32>,
+    // activation(z), same shape as z
+    pub a: DMatrix<f32>,
+    pub dz: DMatrix<f32>,
     pub dw: DMatrix<f32>,
     pub db: DVector<f32>,
     pub momentum_dw: DMatrix<f32>,
@@ -212,6 +244,29 @@ pub struct Layer {
 
 }
 
+impl Layer {
+    pub fn new<R: RandomInitializer>(def: &LayerDefinition, num_inputs: usize, rand_initializer: R, rng: &mut rand_pcg::Pcg32) -> Layer {
+        let num_activations = def.num_activations;
+        let weights = rand_initializer.weights(num_activations, num_inputs, rng);
+
+        Layer {
+            num_activations,
+            intercepts: DVector::zeros(num_activations),
+            weights,
+            activation_type: def.activation_type.clone(),
+            z: DMatrix::zeros(0, 0),
+            a: DMatrix::zeros(0, 0),
+            dz: DMatrix::zeros(0, 0),
+            dw: DMatrix::zeros(num_activations, num_inputs),
+            db: DVector::zeros(num_activations),
+            momentum_dw: DMatrix::zeros(num_activations, num_inputs),
+            momentum_db: DVector::zeros(num_activations),
+            rmsp_dw: DMatrix::zeros(num_activations, num_inputs),
+            rmsp_db: DVector::zeros(num_activations),
+        }
+    }
+}
+
 
 #[derive(Debug)]
 pub struct LayerDefinition {
@@ -284,6 +339,8 @@ impl std::fmt::Debug for ActivationType {
             ActivationType::Relu => write!(f, "Relu"),
             ActivationType::Tanh => write!(f, "Tanh"),
             ActivationType::SoftMax => write!(f, "SoftMax"),
+            ActivationType::QuietSoftMax => write!(f, "QuietSoftMax"),
+            ActivationType::Identity => write!(f, "Identity"),
         }
     }
 }
@@ -295,7 +352,23 @@ impl Clone for ActivationType {
             ActivationType::Sigmoid => ActivationType::Sigmoid,
             ActivationType::Relu => ActivationType::Relu,
             ActivationType::Tanh => ActivationType::Tanh,
-            ActivationType::SoftMax => ActivationType::SoftMax
+            ActivationType::SoftMax => ActivationType::SoftMax,
+            ActivationType::QuietSoftMax => ActivationType::QuietSoftMax,
+            ActivationType::Identity => ActivationType::Identity,
+        }
+    }
+}
+
+impl ActivationType {
+    fn from_str(s: &str) -> Result<ActivationType, Box<dyn std::error::Error>> {
+        match s {
+            "Sigmoid" => Ok(ActivationType::Sigmoid),
+            "Relu" => Ok(ActivationType::Relu),
+            "Tanh" => Ok(ActivationType::Tanh),
+            "SoftMax" => Ok(ActivationType::SoftMax),
+            "QuietSoftMax" => Ok(ActivationType::QuietSoftMax),
+            "Identity" => Ok(ActivationType::Identity),
+            other => Err(format!("unknown activation type '{}'", other).into()),
         }
     }
 }
@@ -309,4 +382,221 @@ impl std::fmt::Debug for Layer {
         \t\tactivation_type : {:?}
     }}", self.num_activations, self.weights.shape(), self.activation_type)
     }
+}
+
+fn matrix_to_json(m: &DMatrix<f32>) -> Value {
+    json!({
+        "rows": m.nrows(),
+        "cols": m.ncols(),
+        "data": m.iter().cloned().collect::<Vec<f32>>(),
+    })
+}
+
+fn matrix_from_json(v: &Value) -> Result<DMatrix<f32>, Box<dyn std::error::Error>> {
+    let rows = v["rows"].as_u64().ok_or("missing matrix rows")? as usize;
+    let cols = v["cols"].as_u64().ok_or("missing matrix cols")? as usize;
+    let data: Vec<f32> = serde_json::from_value(v["data"].clone())?;
+
+    Ok(DMatrix::from_vec(rows, cols, data))
+}
+
+fn vector_to_json(v: &DVector<f32>) -> Value {
+    json!(v.iter().cloned().collect::<Vec<f32>>())
+}
+
+fn vector_from_json(v: &Value) -> Result<DVector<f32>, Box<dyn std::error::Error>> {
+    let data: Vec<f32> = serde_json::from_value(v.clone())?;
+
+    Ok(DVector::from_vec(data))
+}
+
+impl HyperParams {
+    fn to_json(&self) -> Value {
+        json!({
+            "max_accuracy_threshold": self.max_accuracy_threshold,
+            "max_epochs": self.max_epochs,
+            "momentum_beta": self.momentum_beta,
+            "rms_prop_beta": self.rms_prop_beta,
+            "mini_batch_size": self.mini_batch_size,
+            "learning_rate": self.learning_rate,
+            "optimization_type": format!("{:?}", self.optimization_type),
+            "l2_regularization": self.l2_regularization,
+            "criterion": {
+                "name": self.criterion.name(),
+                "reduction": format!("{:?}", self.criterion.reduction()),
+            },
+        })
+    }
+
+    fn from_json(v: &Value) -> Result<HyperParams, Box<dyn std::error::Error>> {
+        let criterion_json = &v["criterion"];
+        let criterion = criterion_from_name(
+            criterion_json["name"].as_str().ok_or("missing criterion name")?,
+            Reduction::from_str(criterion_json["reduction"].as_str().ok_or("missing criterion reduction")?)?,
+        )?;
+
+        Ok(HyperParams {
+            max_accuracy_threshold: v["max_accuracy_threshold"].as_f64().ok_or("missing max_accuracy_threshold")? as f32,
+            max_epochs: v["max_epochs"].as_u64().ok_or("missing max_epochs")? as u32,
+            momentum_beta: v["momentum_beta"].as_f64().ok_or("missing momentum_beta")? as f32,
+            rms_prop_beta: v["rms_prop_beta"].as_f64().ok_or("missing rms_prop_beta")? as f32,
+            mini_batch_size: v["mini_batch_size"].as_u64().ok_or("missing mini_batch_size")? as usize,
+            learning_rate: v["learning_rate"].as_f64().ok_or("missing learning_rate")? as f32,
+            optimization_type: OptimizationType::from_str(v["optimization_type"].as_str().ok_or("missing optimization_type")?)?,
+            l2_regularization: v["l2_regularization"].as_f64().map(|l| l as f32),
+            criterion,
+        })
+    }
+}
+
+impl NNModel {
+    /// Persists the trained weights (not the transient `z`/`a`/`dz`/gradient/momentum
+    /// state, which only makes sense mid-training) as JSON at `path`.
+    pub fn save(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let layers: Vec<Value> = self.layers.iter().map(|layer| json!({
+            "num_activations": layer.num_activations,
+            "activation_type": format!("{:?}", layer.activation_type),
+            "weights": matrix_to_json(&layer.weights),
+            "intercepts": vector_to_json(&layer.intercepts),
+        })).collect();
+
+        let training_info = self.training_info.as_ref().map(|ti| json!({
+            "hyper_params": ti.hyper_params.to_json(),
+            "num_epochs_used": ti.num_epochs_used,
+            "num_iterations_used": ti.num_iterations_used,
+            "loss": ti.loss,
+        }));
+
+        let v = json!({
+            "num_features": self.num_features,
+            "num_classes": self.num_classes,
+            "layers": layers,
+            "training_info": training_info,
+        });
+
+        fs::write(path, serde_json::to_string_pretty(&v)?)?;
+
+        Ok(())
+    }
+
+    /// Loads a model previously written by `save`. Layers come back with freshly
+    /// zeroed `z`/`a`/`dz`/gradient/momentum state, ready for `Prediction::predict`
+    /// or to resume training.
+    pub fn load(path: &str) -> Result<NNModel, Box<dyn std::error::Error>> {
+        let content = fs::read_to_string(path)?;
+        let v: Value = serde_json::from_str(&content)?;
+
+        let num_features = v["num_features"].as_u64().ok_or("missing num_features")? as usize;
+
+        let layers = v["layers"].as_array().ok_or("missing layers")?
+            .iter()
+            .map(|layer_json| {
+                let weights = matrix_from_json(&layer_json["weights"])?;
+                let intercepts = vector_from_json(&layer_json["intercepts"])?;
+                let num_activations = layer_json["num_activations"].as_u64().ok_or("missing num_activations")? as usize;
+                let activation_type = ActivationType::from_str(
+                    layer_json["activation_type"].as_str().ok_or("missing activation_type")?)?;
+                let num_inputs = weights.ncols();
+
+                Ok(Layer {
+                    num_activations,
+                    intercepts,
+                    weights,
+                    activation_type,
+                    z: DMatrix::zeros(0, 0),
+                    a: DMatrix::zeros(0, 0),
+                    dz: DMatrix::zeros(0, 0),
+                    dw: DMatrix::zeros(num_activations, num_inputs),
+                    db: DVector::zeros(num_activations),
+                    momentum_dw: DMatrix::zeros(num_activations, num_inputs),
+                    momentum_db: DVector::zeros(num_activations),
+                    rmsp_dw: DMatrix::zeros(num_activations, num_inputs),
+                    rmsp_db: DVector::zeros(num_activations),
+                })
+            })
+            .collect::<Result<Vec<Layer>, Box<dyn std::error::Error>>>()?;
+
+        let training_info = match v.get("training_info").filter(|t| !t.is_null()) {
+            Some(ti) => Some(TrainingInfo {
+                hyper_params: HyperParams::from_json(&ti["hyper_params"])?,
+                num_epochs_used: ti["num_epochs_used"].as_u64().ok_or("missing num_epochs_used")? as u32,
+                num_iterations_used: ti["num_iterations_used"].as_u64().ok_or("missing num_iterations_used")? as u32,
+                loss: ti["loss"].as_f64().ok_or("missing loss")? as f32,
+            }),
+            None => None,
+        };
+
+        Ok(NNModel {
+            num_features,
+            num_classes: v["num_classes"].as_u64().ok_or("missing num_classes")? as usize,
+            layers,
+            training_info,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+
+    use super::*;
+
+    #[test]
+    fn save_then_load_round_trips_a_model() {
+        let arch = NeuralNetworkArchitecture {
+            num_features: 3,
+            num_classes: 2,
+            layers: vec![
+                LayerDefinition { activation_type: ActivationType::Relu, num_activations: 4 },
+                LayerDefinition { activation_type: ActivationType::SoftMax, num_activations: 2 },
+            ],
+            rand_initializer: HeUniform,
+        };
+
+        let mut rng = Pcg32::seed_from_u64(3);
+        let layers: Vec<Layer> = {
+            let mut num_inputs = arch.num_features;
+            arch.layers.iter().map(|def| {
+                let layer = Layer::new(def, num_inputs, arch.rand_initializer, &mut rng);
+                num_inputs = def.num_activations;
+                layer
+            }).collect()
+        };
+
+        let model = NNModel {
+            num_features: arch.num_features,
+            num_classes: arch.num_classes,
+            layers,
+            training_info: Some(TrainingInfo {
+                hyper_params: HyperParams::default(),
+                num_epochs_used: 2,
+                num_iterations_used: 17,
+                loss: 0.42,
+            }),
+        };
+
+        let path = std::env::temp_dir().join("rust_nn_save_load_round_trip_test.json");
+        let path = path.to_str().unwrap();
+
+        model.save(path).unwrap();
+        let loaded = NNModel::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.num_features, model.num_features);
+        assert_eq!(loaded.num_classes, model.num_classes);
+        assert_eq!(loaded.layers.len(), model.layers.len());
+
+        for (original, reloaded) in model.layers.iter().zip(loaded.layers.iter()) {
+            assert_eq!(reloaded.num_activations, original.num_activations);
+            assert_eq!(format!("{:?}", reloaded.activation_type), format!("{:?}", original.activation_type));
+            assert_eq!(reloaded.weights, original.weights);
+            assert_eq!(reloaded.intercepts, original.intercepts);
+        }
+
+        let training_info = loaded.training_info.expect("training_info should round-trip");
+        assert_eq!(training_info.num_epochs_used, 2);
+        assert_eq!(training_info.num_iterations_used, 17);
+        assert_eq!(training_info.loss, 0.42);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,139 @@
+use nalgebra::DMatrix;
+
+use crate::neunet::definitions::MLOps;
+
+/// How per-example losses are aggregated into the scalar a `Criterion` reports.
+#[derive(Debug, Clone, Copy)]
+pub enum Reduction {
+    Mean,
+    Sum,
+    // No implicit normalization: the raw per-example total is reported as-is, same as
+    // `Sum`, for callers that want to apply their own scaling downstream.
+    None,
+}
+
+impl Reduction {
+    fn apply(&self, total: f32, num_examples: usize) -> f32 {
+        match self {
+            Reduction::Mean => total / num_examples as f32,
+            Reduction::Sum | Reduction::None => total,
+        }
+    }
+
+    pub(crate) fn from_str(s: &str) -> Result<Reduction, Box<dyn std::error::Error>> {
+        match s {
+            "Mean" => Ok(Reduction::Mean),
+            "Sum" => Ok(Reduction::Sum),
+            "None" => Ok(Reduction::None),
+            other => Err(format!("unknown reduction '{}'", other).into()),
+        }
+    }
+}
+
+/// A pluggable loss: `loss` reports the scalar cost for a mini-batch, `output_delta`
+/// gives the `dz` to seed backprop at the output layer. `name`/`reduction` identify the
+/// concrete criterion so `HyperParams::to_json`/`from_json` can round-trip it without
+/// needing `Box<dyn Criterion>` itself to be (de)serializable.
+pub trait Criterion {
+    fn name(&self) -> &'static str;
+    fn reduction(&self) -> Reduction;
+    fn loss(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> f32;
+    fn output_delta(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> DMatrix<f32>;
+}
+
+/// Reconstructs the concrete `Criterion` named by `to_json`'s `"name"`/`"reduction"`
+/// pair, mirroring `ActivationType::from_str`/`OptimizationType::from_str`.
+pub(crate) fn criterion_from_name(name: &str, reduction: Reduction) -> Result<Box<dyn Criterion>, Box<dyn std::error::Error>> {
+    match name {
+        "BinaryCrossEntropy" => Ok(Box::new(BinaryCrossEntropy { reduction })),
+        "CrossEntropy" => Ok(Box::new(CrossEntropy { reduction })),
+        "MeanSquaredError" => Ok(Box::new(MeanSquaredError { reduction })),
+        other => Err(format!("unknown criterion '{}'", other).into()),
+    }
+}
+
+pub struct BinaryCrossEntropy {
+    pub reduction: Reduction,
+}
+
+impl Criterion for BinaryCrossEntropy {
+    fn name(&self) -> &'static str {
+        "BinaryCrossEntropy"
+    }
+
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    fn loss(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> f32 {
+        let total: f32 = y.iter().zip(y_hat.iter())
+            .map(|(&yi, &y_hat_i)| MLOps.loss_from_pred(yi, y_hat_i))
+            .sum();
+
+        self.reduction.apply(total, y.ncols())
+    }
+
+    fn output_delta(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> DMatrix<f32> {
+        y_hat - y
+    }
+}
+
+pub struct CrossEntropy {
+    pub reduction: Reduction,
+}
+
+impl Criterion for CrossEntropy {
+    fn name(&self) -> &'static str {
+        "CrossEntropy"
+    }
+
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    fn loss(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> f32 {
+        let total: f32 = y.column_iter().zip(y_hat.column_iter())
+            .map(|(yi, y_hat_i)| MLOps.cross_entropy_from_pred(&yi.clone_owned(), &y_hat_i.clone_owned()))
+            .sum();
+
+        self.reduction.apply(total, y.ncols())
+    }
+
+    fn output_delta(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> DMatrix<f32> {
+        // Combined softmax + cross-entropy gradient also simplifies to a - y.
+        y_hat - y
+    }
+}
+
+/// Unlike `BinaryCrossEntropy`/`CrossEntropy`, whose `output_delta` is only correct
+/// because it's paired with the output activation whose gradient it already folds in
+/// (Sigmoid, SoftMax), `MeanSquaredError::output_delta` below is `2 * (y_hat - y)` with
+/// no activation derivative applied at all. That's only the right `dz` when the output
+/// layer's activation is `ActivationType::Identity` (`g'(z) = 1` everywhere); pairing
+/// `MeanSquaredError` with any other output activation silently computes the wrong
+/// gradient.
+pub struct MeanSquaredError {
+    pub reduction: Reduction,
+}
+
+impl Criterion for MeanSquaredError {
+    fn name(&self) -> &'static str {
+        "MeanSquaredError"
+    }
+
+    fn reduction(&self) -> Reduction {
+        self.reduction
+    }
+
+    fn loss(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> f32 {
+        let total: f32 = y.iter().zip(y_hat.iter())
+            .map(|(&yi, &y_hat_i)| (y_hat_i - yi).powi(2))
+            .sum();
+
+        self.reduction.apply(total, y.ncols())
+    }
+
+    fn output_delta(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> DMatrix<f32> {
+        (y_hat - y) * 2.0
+    }
+}
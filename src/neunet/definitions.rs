@@ -1,91 +1,405 @@
-use std::f64::EPSILON;
-
 use nalgebra::{DMatrix, DVector, DVectorSlice};
 
+use crate::neunet::api::defs::{ActivationType, Layer, NeuralNetworkArchitecture, RandomInitializer};
+use crate::neunet::criterion::Criterion;
+use crate::neunet::optimization::{BackProp, ForwardProp};
+
 pub struct MLOps;
 
 impl MLOps {
-    pub fn hypothesis(&self, w: &DVector<f64>, x: &DVectorSlice<f64>, b: f64) -> f64 {
+    pub fn hypothesis(&self, w: &DVector<f32>, x: &DVectorSlice<f32>, b: f32) -> f32 {
         w.dot(x) + b
     }
 
-    pub fn sigmoid(&self, z: f64) -> f64 {
-        1.0_f64 / (1.0_f64 + (-z).exp())
+    pub fn sigmoid(&self, z: f32) -> f32 {
+        1.0_f32 / (1.0_f32 + (-z).exp())
     }
 
-    pub fn sigmoid_derivative(&self, z: f64) -> f64 {
+    pub fn sigmoid_derivative(&self, z: f32) -> f32 {
         let s = self.sigmoid(z);
-        s * (1.0_f64 - s)
+        s * (1.0_f32 - s)
     }
 
-    pub fn relu(&self, z: f64) -> f64 {
-        z.max(0.0_f64)
+    pub fn relu(&self, z: f32) -> f32 {
+        z.max(0.0_f32)
     }
 
-    pub fn relu_derivative(&self, z: f64) -> f64 {
-        if z >= 0.0_f64 {
-            1.0_f64
+    pub fn relu_derivative(&self, z: f32) -> f32 {
+        if z >= 0.0_f32 {
+            1.0_f32
         } else {
-            0.0_f64
+            0.0_f32
         }
     }
 
-    pub fn tanh(&self, z: f64) -> f64 {
+    pub fn tanh(&self, z: f32) -> f32 {
         z.tanh()
     }
 
-    pub fn tanh_derivative(&self, z: f64) -> f64 {
-        1.0_f64 - z.tanh().powi(2)
+    pub fn tanh_derivative(&self, z: f32) -> f32 {
+        1.0_f32 - z.tanh().powi(2)
     }
 
-    pub fn soft_max(&self, v: DVector<f64>) -> DVector<f64> {
-        let mut sum = 0.0_f64;
-        for e in v.iter() {
-            sum += e.exp();
-        }
+    /// Numerically stable softmax: subtracts the max element before exponentiating
+    /// so large logits don't overflow `exp`.
+    pub fn soft_max(&self, v: DVector<f32>) -> DVector<f32> {
+        let max = v.max();
+        let exp = v.map(|e| (e - max).exp());
+        let sum = exp.sum();
 
-        v / sum
+        exp / sum
     }
 
-    pub fn soft_max_derivative(&self, v: DVector<f64>) -> DVector<f64> {
-        let mut sum = 0.0_f64;
-        for e in v.iter() {
-            sum += e.exp();
-        }
+    pub fn soft_max_derivative(&self, v: DVector<f32>) -> DVector<f32> {
+        let max = v.max();
+        let exp = v.map(|e| (e - max).exp());
+        let sum = exp.sum();
 
-        v.map(|e| e * (sum - e) / sum.powi(2))
+        exp.map(|e| e * (sum - e) / sum.powi(2))
     }
 
-    pub fn loss(&self, y: f64, w: &DVector<f64>, x: &DVectorSlice<f64>, b: f64) -> f64 {
+    /// Softmax with an implicit always-zero logit folded into the denominator:
+    /// `qsoftmax_i = exp(x_i - m) / (exp(-m) + Σ_j exp(x_j - m))`. Letting the extra
+    /// `exp(-m)` term compete with the real logits lets every output probability shrink
+    /// toward zero when nothing is strongly activated, instead of forcing the outputs
+    /// to always sum to one.
+    pub fn quiet_soft_max(&self, v: DVector<f32>) -> DVector<f32> {
+        let max = v.max();
+        let exp = v.map(|e| (e - max).exp());
+        let sum = exp.sum() + (-max).exp();
+
+        exp / sum
+    }
+
+    pub fn quiet_soft_max_derivative(&self, v: DVector<f32>) -> DVector<f32> {
+        let max = v.max();
+        let exp = v.map(|e| (e - max).exp());
+        let sum = exp.sum() + (-max).exp();
+
+        exp.map(|e| e * (sum - e) / sum.powi(2))
+    }
+
+    pub fn loss(&self, y: f32, w: &DVector<f32>, x: &DVectorSlice<f32>, b: f32) -> f32 {
         let y_hat = self.sigmoid(self.hypothesis(w, x, b));
         -(y * y_hat.ln() + (1. - y) * (1. - y_hat).ln())
     }
 
-    pub fn loss_from_pred(&self, y: f64, y_hat: f64) -> f64 {
+    pub fn loss_from_pred(&self, y: f32, y_hat: f32) -> f32 {
         -(y * y_hat.ln() + (1. - y) * (1. - y_hat).ln())
     }
-}
 
-pub enum OptimizationType {
-    stochastic_gradient_descent,
-    batch_gradient_descent,
-    adam,
+    /// Mean loss over an entire mini-batch (one column per example).
+    pub fn loss_from_pred_batch(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> f32 {
+        let num_examples = y.ncols() as f32;
+
+        let total: f32 = y.iter().zip(y_hat.iter())
+            .map(|(&yi, &y_hat_i)| self.loss_from_pred(yi, y_hat_i))
+            .sum();
+
+        total / num_examples
+    }
+
+    /// L2 (weight-decay) penalty `(lambda / 2m) * Σ ||W_l||²` across all layers, added to
+    /// the reported loss when `HyperParams::l2_regularization` is set.
+    pub fn l2_penalty(&self, layers: &[Layer], lambda: f32, num_examples: f32) -> f32 {
+        let sum_of_squares: f32 = layers.iter()
+            .map(|layer| layer.weights.iter().map(|w| w * w).sum::<f32>())
+            .sum();
+
+        (lambda / (2.0 * num_examples)) * sum_of_squares
+    }
+
+    /// Cross-entropy for a single one-hot encoded example: `-Σ y_i ln(y_hat_i + EPSILON)`.
+    /// The epsilon keeps the loss finite when a predicted probability underflows to zero.
+    pub fn cross_entropy_from_pred(&self, y: &DVector<f32>, y_hat: &DVector<f32>) -> f32 {
+        -y.iter().zip(y_hat.iter())
+            .map(|(&yi, &y_hat_i)| yi * (y_hat_i + f32::EPSILON).ln())
+            .sum::<f32>()
+    }
+
+    /// Mean cross-entropy over an entire mini-batch of one-hot encoded examples
+    /// (one column per example).
+    pub fn cross_entropy(&self, y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> f32 {
+        let num_examples = y.ncols() as f32;
+
+        let total: f32 = y.column_iter().zip(y_hat.column_iter())
+            .map(|(yi, y_hat_i)| self.cross_entropy_from_pred(&yi.clone_owned(), &y_hat_i.clone_owned()))
+            .sum();
+
+        total / num_examples
+    }
+
+    /// Applies `activation_type` to a whole `z` matrix (features x batch) as produced by
+    /// `W * a_prev + b`, column-wise for `SoftMax`/`QuietSoftMax` and element-wise otherwise.
+    pub fn activate(&self, z: &DMatrix<f32>, activation_type: &ActivationType) -> DMatrix<f32> {
+        match activation_type {
+            ActivationType::Sigmoid => z.map(|e| self.sigmoid(e)),
+            ActivationType::Relu => z.map(|e| self.relu(e)),
+            ActivationType::Tanh => z.map(|e| self.tanh(e)),
+            ActivationType::SoftMax => {
+                let mut a = z.clone();
+                for mut col in a.column_iter_mut() {
+                    let softmaxed = self.soft_max(col.clone_owned());
+                    col.copy_from(&softmaxed);
+                }
+                a
+            }
+            ActivationType::QuietSoftMax => {
+                let mut a = z.clone();
+                for mut col in a.column_iter_mut() {
+                    let softmaxed = self.quiet_soft_max(col.clone_owned());
+                    col.copy_from(&softmaxed);
+                }
+                a
+            }
+            ActivationType::Identity => z.clone(),
+        }
+    }
+
+    /// Element-wise derivative of `activation_type` w.r.t. its pre-activation `z`, used
+    /// to propagate `dz` back through a hidden layer. `SoftMax` and `QuietSoftMax` are only
+    /// supported as the output activation, where the gradient folds into `dz = a - y` instead.
+    pub fn activate_derivative(&self, z: &DMatrix<f32>, activation_type: &ActivationType) -> DMatrix<f32> {
+        match activation_type {
+            ActivationType::Sigmoid => z.map(|e| self.sigmoid_derivative(e)),
+            ActivationType::Relu => z.map(|e| self.relu_derivative(e)),
+            ActivationType::Tanh => z.map(|e| self.tanh_derivative(e)),
+            ActivationType::SoftMax => unimplemented!("softmax is only supported as the output activation"),
+            ActivationType::QuietSoftMax => unimplemented!("quiet softmax is only supported as the output activation"),
+            ActivationType::Identity => z.map(|_| 1.0_f32),
+        }
+    }
 }
 
-pub enum ActivationType {
-    sigmoid,
-    relu,
-    soft_max,
+pub struct NeuralNetwork {
+    pub layers: Vec<Layer>,
 }
 
+impl NeuralNetwork {
+    pub fn new<R: RandomInitializer + Clone>(arch: &NeuralNetworkArchitecture<R>, rng: &mut rand_pcg::Pcg32) -> NeuralNetwork {
+        let mut layers = Vec::with_capacity(arch.layers.len());
+        let mut num_inputs = arch.num_features;
 
-pub struct Layer {
-    pub intercepts: DVector<f64>,
-    pub weights: DMatrix<f64>,
-    pub activation_type: ActivationType,
+        for def in &arch.layers {
+            layers.push(Layer::new(def, num_inputs, arch.rand_initializer.clone(), rng));
+            num_inputs = def.num_activations;
+        }
+
+        NeuralNetwork { layers }
+    }
 }
 
-pub struct NeuralNetwork {
-    pub layers: Vec<Layer>
+/// Finite-difference check of `back_prop`'s analytic gradients, for a small random
+/// `network` and mini-batch (`data`/`y`), under whichever `criterion` the caller is
+/// validating. Perturbs each weight and bias by `+epsilon` and `-epsilon` (epsilon ~=
+/// 1e-4), recomputes `criterion.loss` both ways (plus `MLOps::l2_penalty` when
+/// `l2_regularization` is set, matching `StochasticGradientDescent::optimize`'s cost) to
+/// form the numeric estimate `(loss(+epsilon) - loss(-epsilon)) / (2 * epsilon)`, and
+/// returns the relative error
+/// `||grad_analytic - grad_numeric|| / (||grad_analytic|| + ||grad_numeric||)` between
+/// that estimate and the analytic `dw`/`db` produced by backprop (`dw` also gets the same
+/// `lambda / num_examples * weight` term `optimize` adds before its own weight update).
+/// Callers should assert the result stays small (the textbook bound is ~1e-7, but this
+/// crate's f32 arithmetic can't reach that; see the `gradient_check_matches_analytic_backprop`
+/// test) whenever the optimizer or activation derivatives change.
+pub fn gradient_check(network: &mut NeuralNetwork, data: &DMatrix<f32>, y: &DMatrix<f32>,
+                       criterion: &dyn Criterion, l2_regularization: Option<f32>) -> f32 {
+    const EPSILON: f32 = 1e-4;
+
+    let num_examples = data.ncols() as f32;
+
+    let y_hat = network.forward_prop(data);
+    let output_delta = criterion.output_delta(y, &y_hat);
+    network.back_prop(data, output_delta);
+
+    let mut analytic = Vec::new();
+    let mut numeric = Vec::new();
+
+    let perturbed_loss = |network: &mut NeuralNetwork| {
+        let y_hat = network.forward_prop(data);
+        let loss = criterion.loss(y, &y_hat);
+
+        match l2_regularization {
+            Some(lambda) => loss + MLOps.l2_penalty(&network.layers, lambda, num_examples),
+            None => loss,
+        }
+    };
+
+    for l in 0..network.layers.len() {
+        let (rows, cols) = network.layers[l].weights.shape();
+
+        for r in 0..rows {
+            for c in 0..cols {
+                let mut analytic_dw = network.layers[l].dw[(r, c)];
+                if let Some(lambda) = l2_regularization {
+                    analytic_dw += network.layers[l].weights[(r, c)] * (lambda / num_examples);
+                }
+                analytic.push(analytic_dw);
+
+                let original = network.layers[l].weights[(r, c)];
+
+                network.layers[l].weights[(r, c)] = original + EPSILON;
+                let loss_plus = perturbed_loss(network);
+
+                network.layers[l].weights[(r, c)] = original - EPSILON;
+                let loss_minus = perturbed_loss(network);
+
+                network.layers[l].weights[(r, c)] = original;
+
+                numeric.push((loss_plus - loss_minus) / (2.0 * EPSILON));
+            }
+        }
+
+        for i in 0..network.layers[l].intercepts.len() {
+            analytic.push(network.layers[l].db[i]);
+
+            let original = network.layers[l].intercepts[i];
+
+            network.layers[l].intercepts[i] = original + EPSILON;
+            let loss_plus = perturbed_loss(network);
+
+            network.layers[l].intercepts[i] = original - EPSILON;
+            let loss_minus = perturbed_loss(network);
+
+            network.layers[l].intercepts[i] = original;
+
+            numeric.push((loss_plus - loss_minus) / (2.0 * EPSILON));
+        }
+    }
+
+    let analytic = DVector::from_vec(analytic);
+    let numeric = DVector::from_vec(numeric);
+
+    (&analytic - &numeric).norm() / (analytic.norm() + numeric.norm())
 }
 
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
+
+    use crate::neunet::api::defs::{ActivationType, HeUniform, LayerDefinition, NeuralNetworkArchitecture};
+    use crate::neunet::criterion::{BinaryCrossEntropy, MeanSquaredError, Reduction};
+
+    use super::*;
+
+    // quiet_soft_max_derivative has no caller yet (QuietSoftMax is output-only and, like
+    // SoftMax, gets its gradient folded into a criterion's output_delta instead), so this
+    // checks it the same way gradient_check checks backprop: against a finite-difference
+    // estimate of quiet_soft_max itself.
+    #[test]
+    fn quiet_soft_max_derivative_matches_finite_difference() {
+        const EPSILON: f32 = 1e-4;
+        let v = DVector::from_vec(vec![0.3, -1.2, 2.1]);
+
+        let analytic = MLOps.quiet_soft_max_derivative(v.clone());
+
+        for i in 0..v.len() {
+            let mut plus = v.clone();
+            plus[i] += EPSILON;
+
+            let mut minus = v.clone();
+            minus[i] -= EPSILON;
+
+            let numeric = (MLOps.quiet_soft_max(plus)[i] - MLOps.quiet_soft_max(minus)[i]) / (2.0 * EPSILON);
+
+            assert!((analytic[i] - numeric).abs() < 1e-3,
+                    "index {}: analytic {} vs numeric {}", i, analytic[i], numeric);
+        }
+    }
+
+    #[test]
+    fn gradient_check_matches_analytic_backprop() {
+        let arch = NeuralNetworkArchitecture {
+            num_features: 3,
+            num_classes: 1,
+            layers: vec![
+                LayerDefinition { activation_type: ActivationType::Relu, num_activations: 4 },
+                LayerDefinition { activation_type: ActivationType::Sigmoid, num_activations: 1 },
+            ],
+            rand_initializer: HeUniform,
+        };
+
+        let mut rng = Pcg32::seed_from_u64(42);
+        let mut network = NeuralNetwork::new(&arch, &mut rng);
+
+        let data = DMatrix::from_vec(3, 5, vec![
+            0.1, 0.6, 0.2,
+            0.2, 0.7, 0.4,
+            0.3, 0.8, 0.6,
+            0.4, 0.9, 0.8,
+            0.5, 1.0, 1.0,
+        ]);
+        let y = DMatrix::from_vec(1, 5, vec![1.0, 0.0, 1.0, 0.0, 1.0]);
+
+        let criterion = BinaryCrossEntropy { reduction: Reduction::Mean };
+        let relative_error = gradient_check(&mut network, &data, &y, &criterion, None);
+
+        // f32 arithmetic can't hit the textbook ~1e-7 bound finite differences reach in
+        // f64; this looser bound still catches a genuinely wrong backprop sign or shape.
+        assert!(relative_error < 1e-2, "relative error too high: {}", relative_error);
+    }
+
+    #[test]
+    fn gradient_check_matches_analytic_backprop_with_l2_regularization() {
+        let arch = NeuralNetworkArchitecture {
+            num_features: 3,
+            num_classes: 1,
+            layers: vec![
+                LayerDefinition { activation_type: ActivationType::Relu, num_activations: 4 },
+                LayerDefinition { activation_type: ActivationType::Sigmoid, num_activations: 1 },
+            ],
+            rand_initializer: HeUniform,
+        };
+
+        let mut rng = Pcg32::seed_from_u64(42);
+        let mut network = NeuralNetwork::new(&arch, &mut rng);
+
+        let data = DMatrix::from_vec(3, 5, vec![
+            0.1, 0.6, 0.2,
+            0.2, 0.7, 0.4,
+            0.3, 0.8, 0.6,
+            0.4, 0.9, 0.8,
+            0.5, 1.0, 1.0,
+        ]);
+        let y = DMatrix::from_vec(1, 5, vec![1.0, 0.0, 1.0, 0.0, 1.0]);
+
+        let criterion = BinaryCrossEntropy { reduction: Reduction::Mean };
+        let relative_error = gradient_check(&mut network, &data, &y, &criterion, Some(0.1));
+
+        assert!(relative_error < 1e-2, "relative error too high: {}", relative_error);
+    }
+
+    // MeanSquaredError::output_delta assumes an Identity output activation (see its doc
+    // comment); this is its only valid pairing, unlike BinaryCrossEntropy+Sigmoid or
+    // CrossEntropy+SoftMax which fold the activation derivative into the same formula.
+    #[test]
+    fn gradient_check_matches_analytic_backprop_for_mse_with_identity_output() {
+        let arch = NeuralNetworkArchitecture {
+            num_features: 3,
+            num_classes: 1,
+            layers: vec![
+                LayerDefinition { activation_type: ActivationType::Relu, num_activations: 4 },
+                LayerDefinition { activation_type: ActivationType::Identity, num_activations: 1 },
+            ],
+            rand_initializer: HeUniform,
+        };
+
+        let mut rng = Pcg32::seed_from_u64(42);
+        let mut network = NeuralNetwork::new(&arch, &mut rng);
+
+        let data = DMatrix::from_vec(3, 5, vec![
+            0.1, 0.6, 0.2,
+            0.2, 0.7, 0.4,
+            0.3, 0.8, 0.6,
+            0.4, 0.9, 0.8,
+            0.5, 1.0, 1.0,
+        ]);
+        let y = DMatrix::from_vec(1, 5, vec![1.0, 0.0, 1.0, 0.0, 1.0]);
+
+        let criterion = MeanSquaredError { reduction: Reduction::Mean };
+        let relative_error = gradient_check(&mut network, &data, &y, &criterion, None);
+
+        assert!(relative_error < 1e-2, "relative error too high: {}", relative_error);
+    }
+}
@@ -1,7 +1,160 @@
+use std::fs::File;
 use std::io::*;
 
 use nalgebra::*;
 
+use crate::neunet::transforms::normalize::min_max_normalization;
+
 pub trait DataLoader {
     fn load_data(self, data_path: String, labels_path: String) -> Result<(DMatrix<f32>, DVector<u8>)>;
 }
+
+/// Expands `labels` (one class index per example) into a one-hot `num_classes x
+/// labels.len()` matrix, one column per example, for training against `SoftMax`/
+/// `CrossEntropy` output layers.
+pub fn one_hot(labels: &DVector<u8>, num_classes: usize) -> DMatrix<f32> {
+    DMatrix::from_fn(num_classes, labels.len(), |r, c| {
+        if labels[c] as usize == r { 1.0 } else { 0.0 }
+    })
+}
+
+const IMAGE_MAGIC: u32 = 0x0000_0803;
+const LABEL_MAGIC: u32 = 0x0000_0801;
+
+/// Loads MNIST-style datasets stored in the IDX binary format: a 4-byte big-endian
+/// magic number, a 4-byte big-endian item count, and (for images) 4-byte big-endian
+/// row/column counts, followed by the raw `u8` data.
+pub struct IdxLoader;
+
+impl IdxLoader {
+    fn read_u32_be(reader: &mut impl Read) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        reader.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    fn read_images(&self, path: &str) -> Result<DMatrix<f32>> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let magic = Self::read_u32_be(&mut reader)?;
+        if magic != IMAGE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                   format!("unexpected IDX image magic number {:#010x}", magic)));
+        }
+
+        let num_images = Self::read_u32_be(&mut reader)? as usize;
+        let num_rows = Self::read_u32_be(&mut reader)? as usize;
+        let num_cols = Self::read_u32_be(&mut reader)? as usize;
+        let num_features = num_rows * num_cols;
+
+        let mut pixels = vec![0u8; num_images * num_features];
+        reader.read_exact(&mut pixels)?;
+
+        // IDX stores images row-major, one image after another; nalgebra's DMatrix
+        // is column-major, so build it one example-column at a time.
+        let mut features = DMatrix::from_fn(num_features, num_images, |r, c| pixels[c * num_features + r] as f32);
+        min_max_normalization(&mut features);
+
+        Ok(features)
+    }
+
+    fn read_labels(&self, path: &str) -> Result<DVector<u8>> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let magic = Self::read_u32_be(&mut reader)?;
+        if magic != LABEL_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                   format!("unexpected IDX label magic number {:#010x}", magic)));
+        }
+
+        let num_labels = Self::read_u32_be(&mut reader)? as usize;
+
+        let mut labels = vec![0u8; num_labels];
+        reader.read_exact(&mut labels)?;
+
+        Ok(DVector::from_vec(labels))
+    }
+}
+
+impl DataLoader for IdxLoader {
+    fn load_data(self, data_path: String, labels_path: String) -> Result<(DMatrix<f32>, DVector<u8>)> {
+        let features = self.read_images(&data_path)?;
+        let labels = self.read_labels(&labels_path)?;
+
+        Ok((features, labels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn idx_images(num_images: u32, rows: u32, cols: u32, pixels: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&IMAGE_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&num_images.to_be_bytes());
+        buf.extend_from_slice(&rows.to_be_bytes());
+        buf.extend_from_slice(&cols.to_be_bytes());
+        buf.extend_from_slice(pixels);
+        buf
+    }
+
+    fn idx_labels(num_labels: u32, labels: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&LABEL_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&num_labels.to_be_bytes());
+        buf.extend_from_slice(labels);
+        buf
+    }
+
+    #[test]
+    fn idx_loader_reads_an_in_memory_idx_buffer() {
+        // Two 2x2 images, normalized min/max per-column by read_images, so each
+        // image's own darkest/brightest pixel become 0.0/1.0.
+        let pixels = [0u8, 85, 170, 255, 10, 20, 30, 40];
+        let data_path = std::env::temp_dir().join("rust_nn_idx_images_test.idx");
+        let labels_path = std::env::temp_dir().join("rust_nn_idx_labels_test.idx");
+        let data_path = data_path.to_str().unwrap();
+        let labels_path = labels_path.to_str().unwrap();
+
+        std::fs::write(data_path, idx_images(2, 2, 2, &pixels)).unwrap();
+        std::fs::write(labels_path, idx_labels(2, &[3, 7])).unwrap();
+
+        let (features, labels) = IdxLoader.load_data(data_path.to_string(), labels_path.to_string()).unwrap();
+
+        std::fs::remove_file(data_path).unwrap();
+        std::fs::remove_file(labels_path).unwrap();
+
+        assert_eq!(features.shape(), (4, 2));
+        assert_eq!(labels, DVector::from_vec(vec![3, 7]));
+
+        // min_max_normalization stretches each example-column to [0.0, 1.0].
+        assert_eq!(features.column(0).clone_owned(), DVector::from_vec(vec![0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]));
+        assert_eq!(features.column(1).clone_owned(), DVector::from_vec(vec![0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]));
+    }
+
+    #[test]
+    fn idx_loader_rejects_a_bad_magic_number() {
+        let data_path = std::env::temp_dir().join("rust_nn_idx_bad_magic_test.idx");
+        let data_path = data_path.to_str().unwrap();
+
+        std::fs::write(data_path, idx_images(0, 0, 0, &[])).unwrap();
+        let err = IdxLoader.read_labels(data_path).unwrap_err();
+        std::fs::remove_file(data_path).unwrap();
+
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn one_hot_expands_each_label_into_its_own_column() {
+        let labels = DVector::from_vec(vec![0u8, 2, 1]);
+
+        let expanded = one_hot(&labels, 3);
+
+        assert_eq!(expanded, DMatrix::from_vec(3, 3, vec![
+            1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0,
+            0.0, 1.0, 0.0,
+        ]));
+    }
+}
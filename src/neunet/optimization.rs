@@ -1,135 +1,377 @@
+// StochasticGradientDescent and friends are exercised by this module's tests (see
+// `mbgd_reduces_loss` et al.) rather than by any caller yet, same as the untriggered
+// paths `#![allow(dead_code)]` already covers in api::defs.
+#![allow(dead_code)]
+
 use nalgebra::*;
 
-use crate::neunet::definitions::{ActivationType, MLOps, NeuralNetwork};
+use crate::neunet::api::defs::{HyperParams, Layer, Metrics, OptimizationType, Prediction,
+                                TrainingEval, TrainingMessage, TrainingObserver};
+use crate::neunet::definitions::{MLOps, NeuralNetwork};
+
+// Keeps Adam/RMSProp's denominator away from a divide-by-zero when a gradient is flat.
+const OPTIMIZER_EPSILON: f32 = 1e-8;
+
+/// Applies one gradient-descent step to `layer`'s weights/intercepts, dispatching on
+/// `optimization_type` for how `dw`/`db` are turned into an update:
+/// - `MBGD`: the raw gradient, scaled by `learning_rate`.
+/// - `Momentum`: an exponential moving average of the gradient (`momentum_dw`/`db`),
+///   which damps oscillations across mini-batches.
+/// - `RMSProp`: the gradient scaled down by a moving average of its squared magnitude
+///   (`rmsp_dw`/`db`), which shrinks the step size on noisy/steep dimensions.
+/// - `Adam`: both moving averages combined, each bias-corrected for `iteration` (1-based)
+///   since they start at zero.
+fn apply_update(layer: &mut Layer, learning_rate: f32, optimization_type: &OptimizationType,
+                 momentum_beta: f32, rms_prop_beta: f32, iteration: f32) {
+    match optimization_type {
+        OptimizationType::MBGD => {
+            layer.weights -= layer.dw.clone() * learning_rate;
+            layer.intercepts -= layer.db.clone() * learning_rate;
+        }
+        OptimizationType::Momentum => {
+            layer.momentum_dw = layer.momentum_dw.clone() * momentum_beta + layer.dw.clone() * (1.0 - momentum_beta);
+            layer.momentum_db = layer.momentum_db.clone() * momentum_beta + layer.db.clone() * (1.0 - momentum_beta);
+
+            layer.weights -= layer.momentum_dw.clone() * learning_rate;
+            layer.intercepts -= layer.momentum_db.clone() * learning_rate;
+        }
+        OptimizationType::RMSProp => {
+            layer.rmsp_dw = layer.rmsp_dw.clone() * rms_prop_beta + layer.dw.map(|d| d * d) * (1.0 - rms_prop_beta);
+            layer.rmsp_db = layer.rmsp_db.clone() * rms_prop_beta + layer.db.map(|d| d * d) * (1.0 - rms_prop_beta);
+
+            let dw_update = layer.dw.zip_map(&layer.rmsp_dw, |dw, s| dw / (s.sqrt() + OPTIMIZER_EPSILON));
+            let db_update = layer.db.zip_map(&layer.rmsp_db, |db, s| db / (s.sqrt() + OPTIMIZER_EPSILON));
+
+            layer.weights -= dw_update * learning_rate;
+            layer.intercepts -= db_update * learning_rate;
+        }
+        OptimizationType::Adam => {
+            layer.momentum_dw = layer.momentum_dw.clone() * momentum_beta + layer.dw.clone() * (1.0 - momentum_beta);
+            layer.momentum_db = layer.momentum_db.clone() * momentum_beta + layer.db.clone() * (1.0 - momentum_beta);
+            layer.rmsp_dw = layer.rmsp_dw.clone() * rms_prop_beta + layer.dw.map(|d| d * d) * (1.0 - rms_prop_beta);
+            layer.rmsp_db = layer.rmsp_db.clone() * rms_prop_beta + layer.db.map(|d| d * d) * (1.0 - rms_prop_beta);
+
+            let momentum_dw_hat = layer.momentum_dw.clone() / (1.0 - momentum_beta.powf(iteration));
+            let momentum_db_hat = layer.momentum_db.clone() / (1.0 - momentum_beta.powf(iteration));
+            let rmsp_dw_hat = layer.rmsp_dw.clone() / (1.0 - rms_prop_beta.powf(iteration));
+            let rmsp_db_hat = layer.rmsp_db.clone() / (1.0 - rms_prop_beta.powf(iteration));
+
+            let dw_update = momentum_dw_hat.zip_map(&rmsp_dw_hat, |m, s| m / (s.sqrt() + OPTIMIZER_EPSILON));
+            let db_update = momentum_db_hat.zip_map(&rmsp_db_hat, |m, s| m / (s.sqrt() + OPTIMIZER_EPSILON));
+
+            layer.weights -= dw_update * learning_rate;
+            layer.intercepts -= db_update * learning_rate;
+        }
+    }
+}
 
 trait Optimizer {
-    fn optimize(&self,
-                data: &DMatrix<f64>,
-                labels: &DVector<f64>) -> ();
+    fn optimize(&mut self,
+                data: &DMatrix<f32>,
+                labels: &DMatrix<f32>,
+                observer: &dyn TrainingObserver) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Picks `y`'s/`y_hat`'s predicted class per example (argmax over the rows, or a 0.5
+/// threshold for a single-row binary output) and reports the resulting confusion matrix
+/// and per-class/overall accuracy.
+fn evaluate(y: &DMatrix<f32>, y_hat: &DMatrix<f32>) -> TrainingEval {
+    fn predicted_class(column: &[f32]) -> usize {
+        if column.len() == 1 {
+            if column[0] >= 0.5 { 1 } else { 0 }
+        } else {
+            column.iter().enumerate()
+                .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+                .map(|(i, _)| i)
+                .unwrap()
+        }
+    }
+
+    let num_classes = y.nrows().max(2);
+    let mut confusion_matrix: DMatrix<usize> = DMatrix::zeros(num_classes, num_classes);
+
+    for (actual_col, predicted_col) in y.column_iter().zip(y_hat.column_iter()) {
+        let actual: Vec<f32> = actual_col.iter().cloned().collect();
+        let predicted: Vec<f32> = predicted_col.iter().cloned().collect();
+
+        confusion_matrix[(predicted_class(&actual), predicted_class(&predicted))] += 1;
+    }
+
+    let num_examples = y.ncols();
+    let correct: usize = (0..num_classes).map(|i| confusion_matrix[(i, i)]).sum();
+    let accuracy = correct as f32 / num_examples as f32;
+
+    let labels_accuracies = (0..num_classes).map(|i| {
+        let total: usize = confusion_matrix.row(i).iter().sum();
+        if total == 0 { 0.0 } else { confusion_matrix[(i, i)] as f32 / total as f32 }
+    }).collect();
+
+    TrainingEval {
+        confusion_matrix_dim: num_classes,
+        confusion_matrix,
+        labels_accuracies,
+        accuracy,
+    }
 }
 
 struct StochasticGradientDescent {
-    pub learning_rate: f64,
+    pub hyper_params: HyperParams,
     // 0.0001
-    pub stop_cost_quota: f64,
+    pub stop_cost_quota: f32,
     // 10 ^ -4
     pub network: NeuralNetwork,
 }
 
-trait ForwardProp {
-    fn forward_prop(&self, inputs: DVector<f64>) -> DVector<f64>;
+// pub(crate) so `definitions::gradient_check` can drive them directly.
+pub(crate) trait ForwardProp {
+    // Runs the whole mini-batch (features x batch) through every layer at once,
+    // caching each layer's `z`/`a` for the backward pass.
+    fn forward_prop(&mut self, inputs: &DMatrix<f32>) -> DMatrix<f32>;
 }
 
-struct BackPropOut {
-    weights: DVector<f64>,
-    intercepts: DVector<f64>
-}
-
-trait BackProp {
-    fn back_prop(&self, inputs: DVector<f64>) -> BackPropOut;
+pub(crate) trait BackProp {
+    // Mutates each layer's `dz`/`dw`/`db` in place from its cached `z`/`a`.
+    // `input` is the mini-batch that was last passed to `forward_prop`, needed as
+    // `a_prev` when computing the first layer's `dw`. `output_delta` is the `dz` to
+    // seed the output layer with, as produced by a `Criterion::output_delta`.
+    fn back_prop(&mut self, input: &DMatrix<f32>, output_delta: DMatrix<f32>) -> ();
 }
 
 impl ForwardProp for NeuralNetwork {
-    fn forward_prop(&self, inputs: DVector<f64>) -> DVector<f64> {
-        let mut current = inputs;
+    fn forward_prop(&mut self, inputs: &DMatrix<f32>) -> DMatrix<f32> {
+        let mut a_prev = inputs.clone();
+
+        for layer in &mut self.layers {
+            let mut z = &layer.weights * &a_prev;
+            for mut col in z.column_iter_mut() {
+                col += &layer.intercepts;
+            }
+
+            let a = MLOps.activate(&z, &layer.activation_type);
 
-        for l in &self.layers {
-            current = &l.weights * current + &l.intercepts;
-            current = current.map(|e| MLOps.sigmoid(e));
+            layer.z = z;
+            layer.a = a.clone();
+
+            a_prev = a;
         }
 
-        current
+        a_prev
+    }
+}
+
+impl Prediction for NeuralNetwork {
+    fn predict(&mut self, data: &DMatrix<f32>) -> DMatrix<f32> {
+        self.forward_prop(data)
     }
 }
 
 impl BackProp for NeuralNetwork {
-    fn back_prop(&self, inputs: DVector<f64>) -> BackPropOut {
+    fn back_prop(&mut self, input: &DMatrix<f32>, output_delta: DMatrix<f32>) -> () {
+        let num_examples = input.ncols() as f32;
+        let num_layers = self.layers.len();
+
+        self.layers[num_layers - 1].dz = output_delta;
 
-        let mut current = inputs;
+        for l in (0..num_layers).rev() {
+            if l < num_layers - 1 {
+                let w_next = self.layers[l + 1].weights.clone();
+                let dz_next = self.layers[l + 1].dz.clone();
+                let z_l = self.layers[l].z.clone();
+                let activation_type = self.layers[l].activation_type.clone();
+
+                let da = w_next.transpose() * dz_next;
+                self.layers[l].dz = da.component_mul(&MLOps.activate_derivative(&z_l, &activation_type));
+            }
 
-        for l in &self.layers {
+            let a_prev = if l == 0 {
+                input.clone()
+            } else {
+                self.layers[l - 1].a.clone()
+            };
+
+            let dz = self.layers[l].dz.clone();
+            self.layers[l].dw = (&dz * a_prev.transpose()) / num_examples;
+            self.layers[l].db = DVector::from_iterator(
+                dz.nrows(),
+                (0..dz.nrows()).map(|r| dz.row(r).sum() / num_examples),
+            );
         }
-        unimplemented!()
     }
 }
 
 impl Optimizer for StochasticGradientDescent {
     ///
+    /// Mini-batch gradient descent over the whole network: each iteration pushes the
+    /// batch through `forward_prop`, propagates the error back through every layer via
+    /// `back_prop`, then applies the resulting `dw`/`db` to every layer's weights. When
+    /// `hyper_params.l2_regularization` is set, its weight-decay term is folded into both
+    /// the reported cost and the weight gradients before the update. Every iteration's
+    /// raw and regularized loss, plus an accuracy eval against `data`/`y`, is reported to
+    /// `observer` as a `TrainingMessage` (there's no held-out test set at this layer, so
+    /// `train_eval` and `test_eval` are both computed from the same mini-batch).
     ///
+    /// Stops once `cost` drops below `stop_cost_quota`, `train_eval.accuracy` reaches
+    /// `hyper_params.max_accuracy_threshold`, or `hyper_params.max_epochs` iterations have
+    /// run, whichever comes first. Errors out instead of silently "converging" if `cost`
+    /// ever becomes non-finite (e.g. a diverging learning rate under Adam/RMSProp).
     ///
-    fn optimize(&self,
-                data: &DMatrix<f64>,
-                y: &DVector<f64>) -> () {
-        fn forward_prop(features: &DVectorSlice<f64>, w: &DVector<f64>, b: f64, activation_type: ActivationType) -> f64 {
-            let z_i = MLOps.hypothesis(&w, &features, b);
-
-            match activation_type {
-                ActivationType::sigmoid => MLOps.sigmoid(z_i),
-                _ => MLOps.sigmoid(z_i)
+    fn optimize(&mut self,
+                data: &DMatrix<f32>,
+                y: &DMatrix<f32>,
+                observer: &dyn TrainingObserver) -> Result<(), Box<dyn std::error::Error>> {
+        let num_examples = data.ncols() as f32;
+        let learning_rate = self.hyper_params.learning_rate;
+        let l2_regularization = self.hyper_params.l2_regularization;
+        let optimization_type = self.hyper_params.optimization_type.clone();
+        let momentum_beta = self.hyper_params.momentum_beta;
+        let rms_prop_beta = self.hyper_params.rms_prop_beta;
+        let max_epochs = self.hyper_params.max_epochs;
+        let max_accuracy_threshold = self.hyper_params.max_accuracy_threshold;
+
+        let mut cost = f32::INFINITY;
+        let mut iteration = 0;
+
+        while cost >= self.stop_cost_quota && iteration < max_epochs {
+            let y_hat = self.network.forward_prop(data);
+
+            let raw_cost = self.hyper_params.criterion.loss(y, &y_hat);
+            cost = match l2_regularization {
+                Some(lambda) => raw_cost + MLOps.l2_penalty(&self.network.layers, lambda, num_examples),
+                None => raw_cost,
+            };
+
+            if !cost.is_finite() {
+                return Err(format!("optimize diverged: non-finite cost at iteration {}", iteration).into());
+            }
+
+            let train_eval = evaluate(y, &y_hat);
+            let reached_target_accuracy = train_eval.accuracy >= max_accuracy_threshold;
+
+            observer.emit(TrainingMessage {
+                message: "optimize".to_string(),
+                iteration,
+                epoch: 0,
+                batch_start: 0,
+                metrics: Some(Metrics {
+                    loss: cost,
+                    raw_loss: raw_cost,
+                    test_eval: evaluate(y, &y_hat),
+                    train_eval,
+                }),
+            });
+
+            if reached_target_accuracy {
+                break;
             }
-        }
 
-        fn back_prop(y_hat: f64,
-                     y: f64,
-                     x: &DVectorSlice<f64>,
-                     dw: &mut DVector<f64>,
-                     db: &mut f64) -> () {
-            let dz_i = y_hat - y;
+            let output_delta = self.hyper_params.criterion.output_delta(y, &y_hat);
+            self.network.back_prop(data, output_delta);
 
-            for j in 0..dw.len() {
-                dw[j] += x[j] * dz_i;
+            if let Some(lambda) = l2_regularization {
+                for layer in &mut self.network.layers {
+                    let weights = layer.weights.clone();
+                    layer.dw += weights * (lambda / num_examples);
+                }
             }
 
-            *db += dz_i;
-        }
+            for layer in &mut self.network.layers {
+                apply_update(layer, learning_rate, &optimization_type, momentum_beta, rms_prop_beta, (iteration + 1) as f32);
+            }
 
-        let shape = data.shape();
+            iteration += 1;
+        }
 
-        let num_examples = shape.0;
-        let num_features = shape.1;
+        Ok(())
+    }
+}
 
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_pcg::Pcg32;
 
-        let mut w = DVector::from_vec(vec![0.; num_features]);
-        let mut dw = DVector::from_vec(vec![0.; num_features]);
+    use crate::neunet::api::defs::{ActivationType, HeUniform, HyperParams, LayerDefinition,
+                                    NeuralNetworkArchitecture, OptimizationType, TrainingMessage, TrainingObserver};
+    use crate::neunet::criterion::{BinaryCrossEntropy, Reduction};
 
-        let mut b = 0.;
-        let mut db = 0.;
-        let mut cost = 0.;
+    use super::*;
 
-        let mut converged = false;
+    struct NullObserver;
 
-        let mut iteration = 0;
-        while !converged {
-            println!("SGD iteration {}", iteration);
+    impl TrainingObserver for NullObserver {
+        fn emit(&self, _msg: TrainingMessage) {}
+    }
 
-            for i in 0..num_examples {
-                let x_i = data.column(i);
+    fn small_network_and_batch() -> (NeuralNetwork, DMatrix<f32>, DMatrix<f32>) {
+        let arch = NeuralNetworkArchitecture {
+            num_features: 3,
+            num_classes: 1,
+            layers: vec![
+                LayerDefinition { activation_type: ActivationType::Relu, num_activations: 4 },
+                LayerDefinition { activation_type: ActivationType::Sigmoid, num_activations: 1 },
+            ],
+            rand_initializer: HeUniform,
+        };
+
+        let mut rng = Pcg32::seed_from_u64(11);
+        let network = NeuralNetwork::new(&arch, &mut rng);
+
+        let data = DMatrix::from_vec(3, 5, vec![
+            0.1, 0.6, 0.2,
+            0.2, 0.7, 0.4,
+            0.3, 0.8, 0.6,
+            0.4, 0.9, 0.8,
+            0.5, 1.0, 1.0,
+        ]);
+        let y = DMatrix::from_vec(1, 5, vec![1.0, 0.0, 1.0, 0.0, 1.0]);
+
+        (network, data, y)
+    }
 
-                let y_hat_i = forward_prop(&x_i, &w, b, ActivationType::sigmoid);
+    fn assert_loss_decreases_under(optimization_type: OptimizationType) {
+        let (network, data, y) = small_network_and_batch();
 
-                cost += MLOps.loss_from_pred(y[i], y_hat_i);
+        let hyper_params = HyperParams {
+            learning_rate: 0.1,
+            max_epochs: 5,
+            optimization_type,
+            criterion: Box::new(BinaryCrossEntropy { reduction: Reduction::Mean }),
+            ..HyperParams::default()
+        };
 
-                back_prop(y_hat_i, y[i], &x_i, &mut dw, &mut db);
-            }
+        let mut sgd = StochasticGradientDescent {
+            hyper_params,
+            stop_cost_quota: 0.0,
+            network,
+        };
 
-            println!("Cost {}", cost);
+        let initial_loss = sgd.hyper_params.criterion.loss(&y, &sgd.network.forward_prop(&data));
 
-            for j in 0..num_features {
-                dw[j] /= num_examples as f64;
-                w[j] -= self.learning_rate * dw[j];
-            }
+        sgd.optimize(&data, &y, &NullObserver).unwrap();
 
-            db /= num_examples as f64;
+        let final_loss = sgd.hyper_params.criterion.loss(&y, &sgd.network.forward_prop(&data));
 
-            cost /= num_examples as f64;
+        assert!(final_loss < initial_loss,
+                "{:?}: loss did not decrease ({} -> {})", sgd.hyper_params.optimization_type, initial_loss, final_loss);
+    }
 
-            b -= self.learning_rate * db;
+    #[test]
+    fn mbgd_reduces_loss() {
+        assert_loss_decreases_under(OptimizationType::MBGD);
+    }
 
-            iteration += 1;
+    #[test]
+    fn momentum_reduces_loss() {
+        assert_loss_decreases_under(OptimizationType::Momentum);
+    }
 
-            converged = cost < self.stop_cost_quota;
-        }
+    #[test]
+    fn rms_prop_reduces_loss() {
+        assert_loss_decreases_under(OptimizationType::RMSProp);
+    }
 
-        unimplemented!()
+    #[test]
+    fn adam_reduces_loss() {
+        assert_loss_decreases_under(OptimizationType::Adam);
     }
-}
\ No newline at end of file
+}